@@ -0,0 +1,119 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Whole-database mode: discovers every room in `state_groups_state` and
+//! compresses them all, driving the rooms themselves through `rayon` so
+//! multiple rooms compress concurrently. Each room gets its own connection
+//! out of a small pool, rather than every thread opening its own.
+
+use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+use rayon::prelude::*;
+
+use crate::compressor::{Compressor, Stats};
+use crate::database;
+
+type Pool = r2d2::Pool<PostgresConnectionManager>;
+
+/// The result of compressing a single room, for the per-room summary table.
+pub struct RoomSummary {
+    pub room_id: String,
+    pub original_size: usize,
+    pub compressed_size: usize,
+    pub stats: Stats,
+}
+
+/// Compresses every room found in `state_groups_state`, using up to
+/// `pool_size` concurrent Postgres connections.
+pub fn compress_all_rooms(
+    db_url: &str,
+    level_sizes: &[usize],
+    apply: bool,
+    apply_batch_size: usize,
+    pool_size: u32,
+) -> Vec<RoomSummary> {
+    let manager =
+        PostgresConnectionManager::new(db_url, TlsMode::None).expect("could not configure pool");
+    let pool: Pool = r2d2::Pool::builder()
+        .max_size(pool_size)
+        .build(manager)
+        .expect("could not build connection pool");
+
+    let room_ids = {
+        let conn = pool.get().expect("could not get connection from pool");
+        database::get_room_ids(&conn)
+    };
+
+    // Cap how many rooms compress concurrently to the size of the connection
+    // pool. Left to rayon's default (num_cpus) thread pool, more rooms could
+    // be compressing at once than there are pooled connections, so the
+    // excess threads would block in `pool.get()` until r2d2's connection
+    // timeout elapses and then panic, aborting the whole run.
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(pool_size as usize)
+        .build()
+        .expect("could not build thread pool");
+
+    thread_pool.install(|| {
+        room_ids
+            .par_iter()
+            .map(|room_id| compress_one_room(&pool, room_id, level_sizes, apply, apply_batch_size))
+            .collect()
+    })
+}
+
+fn compress_one_room(
+    pool: &Pool,
+    room_id: &str,
+    level_sizes: &[usize],
+    apply: bool,
+    apply_batch_size: usize,
+) -> RoomSummary {
+    let conn = pool.get().expect("could not get connection from pool");
+
+    let state_group_map = database::get_data_from_db(&conn, room_id, None);
+
+    let original_size = state_group_map
+        .values()
+        .map(|entry| entry.state_map.len())
+        .sum();
+
+    let compressor = Compressor::compress(&state_group_map, level_sizes);
+
+    let compressed_size = compressor
+        .new_state_group_map
+        .values()
+        .map(|entry| entry.state_map.len())
+        .sum();
+
+    if apply {
+        let ids: Vec<i64> = state_group_map.keys().cloned().collect();
+
+        database::apply_changes(
+            &conn,
+            room_id,
+            &state_group_map,
+            &compressor.new_state_group_map,
+            &ids,
+            apply_batch_size,
+        )
+        .unwrap_or_else(|e| panic!("failed to apply changes for room {}: {}", room_id, e));
+    }
+
+    RoomSummary {
+        room_id: room_id.to_string(),
+        original_size,
+        compressed_size,
+        stats: compressor.stats,
+    }
+}
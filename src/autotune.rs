@@ -0,0 +1,115 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Auto-tunes the `--level_sizes` argument by treating it as an
+//! optimization problem: starting from a default configuration, hill-climb
+//! by doubling/halving each level's size in turn and keeping whichever
+//! change improves the compression ratio, until a full pass makes no
+//! further improvement.
+
+use std::collections::BTreeMap;
+
+use crate::compressor::Compressor;
+use crate::StateGroupEntry;
+
+/// Factor by which a level's size is grown or shrunk when hill-climbing.
+const PERTURBATION_FACTOR: f64 = 2.0;
+
+/// The best configuration found, and the compression results it produced.
+pub struct AutoLevelsResult {
+    pub level_sizes: Vec<usize>,
+    pub compressor: Compressor,
+    pub ratio: f64,
+}
+
+/// Hill-climbs from `default_levels`, perturbing each level's size up and
+/// down by [`PERTURBATION_FACTOR`], keeping whichever configuration gives
+/// the best `compressed_summed_size / original_summed_size` ratio. The
+/// number of levels is capped at `max_levels` (levels beyond the cap are
+/// dropped from `default_levels` before the search starts).
+///
+/// `state_group_map` is only read from the database once by the caller and
+/// is reused here for every candidate compression run.
+pub fn auto_tune_levels(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    default_levels: &[usize],
+    max_levels: usize,
+) -> AutoLevelsResult {
+    let original_summed_size: usize = state_group_map
+        .values()
+        .map(|entry| entry.state_map.len())
+        .sum();
+
+    let mut levels: Vec<usize> = default_levels
+        .iter()
+        .take(max_levels.max(1))
+        .cloned()
+        .collect();
+
+    let (mut best_compressor, mut best_ratio) =
+        evaluate(state_group_map, &levels, original_summed_size);
+
+    let mut improved = true;
+    while improved {
+        improved = false;
+
+        for i in 0..levels.len() {
+            let grown = (((levels[i] as f64) * PERTURBATION_FACTOR).round() as usize).max(1);
+            let shrunk = (((levels[i] as f64) / PERTURBATION_FACTOR).round() as usize).max(1);
+
+            for candidate_size in [grown, shrunk] {
+                if candidate_size == levels[i] {
+                    continue;
+                }
+
+                let mut candidate_levels = levels.clone();
+                candidate_levels[i] = candidate_size;
+
+                let (candidate_compressor, candidate_ratio) =
+                    evaluate(state_group_map, &candidate_levels, original_summed_size);
+
+                if candidate_ratio < best_ratio {
+                    levels = candidate_levels;
+                    best_compressor = candidate_compressor;
+                    best_ratio = candidate_ratio;
+                    improved = true;
+                }
+            }
+        }
+    }
+
+    AutoLevelsResult {
+        level_sizes: levels,
+        compressor: best_compressor,
+        ratio: best_ratio,
+    }
+}
+
+fn evaluate(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    level_sizes: &[usize],
+    original_summed_size: usize,
+) -> (Compressor, f64) {
+    let compressor = Compressor::compress(state_group_map, level_sizes);
+
+    let compressed_summed_size: usize = compressor
+        .new_state_group_map
+        .values()
+        .map(|entry| entry.state_map.len())
+        .sum();
+
+    let ratio = (compressed_summed_size as f64) / (original_summed_size as f64);
+
+    (compressor, ratio)
+}
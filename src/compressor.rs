@@ -0,0 +1,186 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implements the core state compression algorithm.
+//!
+//! The compressor walks the state groups in ascending order and assigns each
+//! one to a "level". Each level has a maximum number of state groups it will
+//! hold before it rolls over: once a level fills up, the group that's
+//! currently at the head of it becomes the `prev_state_group` for the next
+//! level up, and the level is reset. This keeps most groups cheap to look up
+//! (they're near the head of a low level) while still bounding the total
+//! number of levels needed to resolve any given group's full state.
+
+use std::collections::BTreeMap;
+
+use crate::StateGroupEntry;
+
+/// Tracks the in-progress chain for a single level of the compression
+/// algorithm.
+#[derive(Debug)]
+struct Level {
+    /// The maximum number of state groups that can be chained together at
+    /// this level before it rolls over.
+    max_length: usize,
+    /// The state group currently at the head of this level's chain, and how
+    /// many groups have been chained below it so far.
+    current: Option<(i64, usize)>,
+}
+
+impl Level {
+    fn new(max_length: usize) -> Level {
+        Level {
+            max_length,
+            current: None,
+        }
+    }
+
+    /// Updates the level with a new state group, returning the group that
+    /// this state group should be based off (i.e. its new
+    /// `prev_state_group`), if any.
+    fn update(&mut self, state_group: i64) -> Option<i64> {
+        let prev = match self.current {
+            Some((head, length)) if length < self.max_length => {
+                self.current = Some((state_group, length + 1));
+                Some(head)
+            }
+            _ => {
+                self.current = Some((state_group, 0));
+                None
+            }
+        };
+
+        prev
+    }
+}
+
+/// Tracks how much work the compressor had to do, for reporting to the user.
+#[derive(Default, Debug, Clone)]
+pub struct Stats {
+    /// Number of times a state group had no suitable group to delta against
+    /// and so had to store its full state.
+    pub resets_no_suitable_prev: usize,
+    /// The total size (number of rows) of the state groups counted in
+    /// `resets_no_suitable_prev`.
+    pub resets_no_suitable_prev_size: usize,
+    /// The number of state groups whose `prev_state_group`/`state_map` ended
+    /// up different to what they started as.
+    pub state_groups_changed: usize,
+}
+
+/// Runs the compression algorithm over a map of state groups, producing a
+/// new map with (hopefully) fewer rows.
+pub struct Compressor {
+    pub new_state_group_map: BTreeMap<i64, StateGroupEntry>,
+    pub stats: Stats,
+    levels: Vec<Level>,
+}
+
+impl Compressor {
+    /// Compresses every group in `state_group_map`, chaining them together
+    /// according to `level_sizes`. The first entry is the lowest (most
+    /// granular) level.
+    pub fn compress(
+        state_group_map: &BTreeMap<i64, StateGroupEntry>,
+        level_sizes: &[usize],
+    ) -> Compressor {
+        let ids: Vec<i64> = state_group_map.keys().cloned().collect();
+        Compressor::compress_ids(state_group_map, level_sizes, &ids)
+    }
+
+    /// Like [`compress`](Compressor::compress), but only assigns levels to
+    /// (and produces output for) the groups listed in `ids`, in the order
+    /// given. Any other entries in `state_group_map` are only used as
+    /// read-only lookups for resolving `prev_state_group` chains - they are
+    /// never themselves placed into a level or reparented.
+    ///
+    /// This is what lets the windowed and incremental modes pull in an
+    /// already-compressed ancestor purely to collapse a cross-window delta
+    /// against, without that ancestor being swept into this run's level
+    /// structure or `new_state_group_map`.
+    pub fn compress_ids(
+        state_group_map: &BTreeMap<i64, StateGroupEntry>,
+        level_sizes: &[usize],
+        ids: &[i64],
+    ) -> Compressor {
+        let mut compressor = Compressor {
+            new_state_group_map: BTreeMap::new(),
+            stats: Stats::default(),
+            levels: level_sizes.iter().map(|&size| Level::new(size)).collect(),
+        };
+
+        for &state_group in ids {
+            compressor.add_state_group(state_group_map, state_group);
+        }
+
+        compressor
+    }
+
+    fn add_state_group(
+        &mut self,
+        state_group_map: &BTreeMap<i64, StateGroupEntry>,
+        state_group: i64,
+    ) {
+        let mut prev_state_group = None;
+
+        for level in &mut self.levels {
+            let candidate = level.update(state_group);
+
+            if candidate.is_some() {
+                prev_state_group = candidate;
+                break;
+            }
+        }
+
+        let old_entry = &state_group_map[&state_group];
+
+        let new_entry = match prev_state_group {
+            Some(prev_sg) => {
+                let prev_state = crate::collapse_state_maps(state_group_map, prev_sg);
+                let full_state = crate::collapse_state_maps(state_group_map, state_group);
+
+                // We only need to store keys that differ from the group
+                // we're deltaing against.
+                let mut delta = state_map::StateMap::new();
+                for ((t, s), e) in full_state.iter() {
+                    if prev_state.get(t, s) != Some(e) {
+                        delta.insert(t, s, e.clone());
+                    }
+                }
+
+                StateGroupEntry {
+                    prev_state_group: Some(prev_sg),
+                    state_map: delta,
+                }
+            }
+            None => {
+                let full_state = crate::collapse_state_maps(state_group_map, state_group);
+
+                self.stats.resets_no_suitable_prev += 1;
+                self.stats.resets_no_suitable_prev_size += full_state.len();
+
+                StateGroupEntry {
+                    prev_state_group: None,
+                    state_map: full_state,
+                }
+            }
+        };
+
+        if &new_entry != old_entry {
+            self.stats.state_groups_changed += 1;
+        }
+
+        self.new_state_group_map.insert(state_group, new_entry);
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for talking to the Postgres database that backs a Synapse
+//! homeserver: fetching the existing state group graph and escaping values
+//! for the generated SQL.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use fallible_iterator::FallibleIterator;
+use postgres::transaction::Transaction;
+use postgres::{Connection, TlsMode};
+use state_map::StateMap;
+use string_cache::DefaultAtom as Atom;
+
+use crate::StateGroupEntry;
+
+/// Opens a connection to the Postgres database at `db_url`. The returned
+/// connection is reused for both reading the existing state and, if
+/// `--apply` is given, writing the compressed result back.
+pub fn connect(db_url: &str) -> Connection {
+    Connection::connect(db_url, TlsMode::None).expect("could not connect to db")
+}
+
+/// Pulls out the current `state_group_edges` and `state_groups_state` rows
+/// for `room_id`, building up the `BTreeMap<i64, StateGroupEntry>` that the
+/// compressor operates on.
+///
+/// If `max_state_group` is given then only state groups with an ID less than
+/// or equal to it are fetched.
+pub fn get_data_from_db(
+    conn: &Connection,
+    room_id: &str,
+    max_state_group: Option<i64>,
+) -> BTreeMap<i64, StateGroupEntry> {
+    get_data_from_db_range(conn, room_id, None, max_state_group)
+}
+
+/// Like [`get_data_from_db`], but additionally restricted to state groups
+/// with an ID greater than or equal to `min_state_group`, if given. Used to
+/// pull in a single window of groups at a time rather than the whole room.
+pub fn get_data_from_db_range(
+    conn: &Connection,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    max_state_group: Option<i64>,
+) -> BTreeMap<i64, StateGroupEntry> {
+    let mut state_group_map = get_initial_state(conn, room_id, min_state_group, max_state_group);
+    get_prev_state_groups(conn, room_id, min_state_group, max_state_group, &mut state_group_map);
+
+    state_group_map
+}
+
+/// Returns the sorted, deduplicated list of state group IDs that appear in
+/// `state_groups_state` for `room_id`, used to carve the room up into
+/// windows for the memory-bounded compression mode.
+pub fn get_state_group_ids(
+    conn: &Connection,
+    room_id: &str,
+    max_state_group: Option<i64>,
+) -> Vec<i64> {
+    get_state_group_ids_range(conn, room_id, None, max_state_group)
+}
+
+/// Like [`get_state_group_ids`], but additionally restricted to state groups
+/// with an ID greater than or equal to `min_state_group`, if given. Used by
+/// the incremental mode to list only the groups produced since the last run.
+pub fn get_state_group_ids_range(
+    conn: &Connection,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    max_state_group: Option<i64>,
+) -> Vec<i64> {
+    let sql = "
+        SELECT DISTINCT state_group FROM state_groups_state
+        WHERE room_id = $1
+            AND ($2::BIGINT IS NULL OR state_group <= $2)
+            AND ($3::BIGINT IS NULL OR state_group >= $3)
+        ORDER BY state_group
+    ";
+
+    let trans = conn.transaction().expect("could not start transaction");
+    let stmt = trans.prepare(sql).expect("could not prepare statement");
+
+    let mut ids = Vec::new();
+
+    let mut rows = stmt
+        .lazy_query(&trans, &[&room_id, &max_state_group, &min_state_group], 1000)
+        .expect("could not run query");
+
+    while let Some(row) = rows.next().expect("error fetching row") {
+        ids.push(row.get(0));
+    }
+
+    ids
+}
+
+/// Returns the distinct room IDs that appear in `state_groups_state`, used
+/// by `--all_rooms` to discover what to compress without the operator
+/// listing every room themselves.
+pub fn get_room_ids(conn: &Connection) -> Vec<String> {
+    let rows = conn
+        .query("SELECT DISTINCT room_id FROM state_groups_state", &[])
+        .expect("could not query room ids");
+
+    rows.iter().map(|row| row.get(0)).collect()
+}
+
+/// Ensures the small marker table used to track incremental-mode progress
+/// exists. Safe to call on every run.
+pub fn ensure_progress_table(conn: &Connection) {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS state_compressor_progress (
+            room_id TEXT PRIMARY KEY,
+            last_compressed_group BIGINT NOT NULL
+        )",
+        &[],
+    )
+    .expect("could not create state_compressor_progress table");
+}
+
+/// Returns the highest state group that incremental mode has already
+/// compressed for `room_id`, if it's been run before.
+pub fn get_watermark(conn: &Connection, room_id: &str) -> Option<i64> {
+    let rows = conn
+        .query(
+            "SELECT last_compressed_group FROM state_compressor_progress WHERE room_id = $1",
+            &[&room_id],
+        )
+        .expect("could not query state_compressor_progress");
+
+    rows.iter().next().map(|row| row.get(0))
+}
+
+/// Records `state_group` as the highest group incremental mode has
+/// compressed for `room_id`, so the next run can pick up from there.
+pub fn set_watermark(conn: &Connection, room_id: &str, state_group: i64) {
+    conn.execute(
+        "INSERT INTO state_compressor_progress (room_id, last_compressed_group)
+            VALUES ($1, $2)
+            ON CONFLICT (room_id) DO UPDATE SET last_compressed_group = $2",
+        &[&room_id, &state_group],
+    )
+    .expect("could not update state_compressor_progress");
+}
+
+/// Writes the changed state groups back to the database, committing once
+/// every `batch_size` changed groups so that a crash mid-run leaves the
+/// tables in a consistent state rather than half-written.
+///
+/// Only groups listed in `ids` are ever written, even if `state_group_map`
+/// / `new_state_group_map` contain other entries (e.g. an already-settled
+/// ancestor pulled in purely to resolve a cross-window `prev_state_group`) -
+/// callers that only compressed a subset of the room must pass that subset
+/// here rather than relying on whatever keys happen to be in the maps.
+///
+/// Before each batch is written, the affected groups are re-checked with
+/// [`crate::collapse_state_maps`] to confirm the compressed state still
+/// matches the original; a mismatch aborts that batch (and the whole run)
+/// without touching the database, rather than only being caught once
+/// everything has already been applied.
+///
+/// Returns the number of state groups that were written.
+pub fn apply_changes(
+    conn: &Connection,
+    room_id: &str,
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    new_state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    ids: &[i64],
+    batch_size: usize,
+) -> Result<usize, String> {
+    let changed: Vec<i64> = ids
+        .iter()
+        .cloned()
+        .filter(|sg| &new_state_group_map[sg] != &state_group_map[sg])
+        .collect();
+
+    let mut groups_applied = 0;
+
+    for batch in changed.chunks(batch_size.max(1)) {
+        for &sg in batch {
+            let expected = crate::collapse_state_maps(state_group_map, sg);
+            let actual = crate::collapse_state_maps(new_state_group_map, sg);
+
+            if expected != actual {
+                return Err(format!(
+                    "state for group {} would not match after compression, aborting before batch containing it was written",
+                    sg
+                ));
+            }
+        }
+
+        let trans = conn
+            .transaction()
+            .map_err(|e| format!("could not start transaction: {}", e))?;
+
+        for &sg in batch {
+            if let Err(e) = write_entry(&trans, room_id, sg, &new_state_group_map[&sg]) {
+                trans.set_rollback();
+                trans.finish().ok();
+                return Err(e);
+            }
+        }
+
+        trans
+            .commit()
+            .map_err(|e| format!("could not commit transaction: {}", e))?;
+
+        groups_applied += batch.len();
+    }
+
+    Ok(groups_applied)
+}
+
+/// Deletes and re-inserts the edge and state rows for a single state group
+/// as part of an in-progress transaction.
+fn write_entry(
+    trans: &Transaction,
+    room_id: &str,
+    state_group: i64,
+    entry: &StateGroupEntry,
+) -> Result<(), String> {
+    trans
+        .execute(
+            "DELETE FROM state_group_edges WHERE state_group = $1",
+            &[&state_group],
+        )
+        .map_err(|e| format!("could not delete edges for {}: {}", state_group, e))?;
+
+    if let Some(prev_sg) = entry.prev_state_group {
+        trans
+            .execute(
+                "INSERT INTO state_group_edges (state_group, prev_state_group) VALUES ($1, $2)",
+                &[&state_group, &prev_sg],
+            )
+            .map_err(|e| format!("could not insert edge for {}: {}", state_group, e))?;
+    }
+
+    trans
+        .execute(
+            "DELETE FROM state_groups_state WHERE state_group = $1",
+            &[&state_group],
+        )
+        .map_err(|e| format!("could not delete state for {}: {}", state_group, e))?;
+
+    for ((etype, state_key), event_id) in entry.state_map.iter() {
+        trans
+            .execute(
+                "INSERT INTO state_groups_state (state_group, room_id, type, state_key, event_id) VALUES ($1, $2, $3, $4, $5)",
+                &[&state_group, &room_id, &etype.as_ref(), &state_key.as_ref(), &event_id.as_ref()],
+            )
+            .map_err(|e| format!("could not insert state for {}: {}", state_group, e))?;
+    }
+
+    Ok(())
+}
+
+/// Fetches the `state_groups_state` rows for the room, filling in the
+/// `state_map` of each `StateGroupEntry`.
+fn get_initial_state(
+    conn: &Connection,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    max_state_group: Option<i64>,
+) -> BTreeMap<i64, StateGroupEntry> {
+    let sql = "
+        SELECT state_group, type, state_key, event_id FROM state_groups_state
+        WHERE room_id = $1
+            AND ($2::BIGINT IS NULL OR state_group <= $2)
+            AND ($3::BIGINT IS NULL OR state_group >= $3)
+    ";
+
+    let trans = conn.transaction().expect("could not start transaction");
+    let stmt = trans.prepare(sql).expect("could not prepare statement");
+
+    let mut state_group_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    let mut rows = stmt
+        .lazy_query(&trans, &[&room_id, &max_state_group, &min_state_group], 1000)
+        .expect("could not run query");
+
+    while let Some(row) = rows.next().expect("error fetching row") {
+        let state_group: i64 = row.get(0);
+        let etype: String = row.get(1);
+        let state_key: String = row.get(2);
+        let event_id: String = row.get(3);
+
+        state_group_map
+            .entry(state_group)
+            .or_insert_with(StateGroupEntry::default)
+            .state_map
+            .insert(&etype, &state_key, event_id.into());
+    }
+
+    state_group_map
+}
+
+/// Fetches the `state_group_edges` rows for the room, filling in the
+/// `prev_state_group` of each `StateGroupEntry` in `state_group_map`. A group
+/// that has an edge but no `state_groups_state` rows gets a new entry with an
+/// empty `state_map`.
+fn get_prev_state_groups(
+    conn: &Connection,
+    room_id: &str,
+    min_state_group: Option<i64>,
+    max_state_group: Option<i64>,
+    state_group_map: &mut BTreeMap<i64, StateGroupEntry>,
+) {
+    let sql = "
+        SELECT state_group, prev_state_group FROM state_group_edges
+        WHERE room_id = $1
+            AND ($2::BIGINT IS NULL OR state_group <= $2)
+            AND ($3::BIGINT IS NULL OR state_group >= $3)
+    ";
+
+    let trans = conn.transaction().expect("could not start transaction");
+    let stmt = trans.prepare(sql).expect("could not prepare statement");
+
+    let mut rows = stmt
+        .lazy_query(&trans, &[&room_id, &max_state_group, &min_state_group], 1000)
+        .expect("could not run query");
+
+    while let Some(row) = rows.next().expect("error fetching row") {
+        let state_group: i64 = row.get(0);
+        let prev_state_group: i64 = row.get(1);
+
+        state_group_map
+            .entry(state_group)
+            .or_insert_with(StateGroupEntry::default)
+            .prev_state_group = Some(prev_state_group);
+    }
+}
+
+/// Fetches the collapsed state for `state_group` directly from the live
+/// database, by walking `state_group_edges` back to a root and then reading
+/// each group's rows out of `state_groups_state`, rather than trusting the
+/// in-memory maps the rest of the tool computed. Used by `--verify_db` to
+/// check what actually landed in Postgres after an apply.
+pub fn fetch_collapsed_state_from_db(
+    conn: &Connection,
+    room_id: &str,
+    state_group: i64,
+) -> StateMap<Atom> {
+    let mut chain = vec![state_group];
+    let mut current = state_group;
+
+    loop {
+        let rows = conn
+            .query(
+                "SELECT prev_state_group FROM state_group_edges WHERE state_group = $1",
+                &[&current],
+            )
+            .expect("could not query state_group_edges");
+
+        match rows.iter().next() {
+            Some(row) => {
+                let prev: i64 = row.get(0);
+                chain.push(prev);
+                current = prev;
+            }
+            None => break,
+        }
+    }
+
+    let mut state_map = StateMap::new();
+
+    for &sg in chain.iter().rev() {
+        let rows = conn
+            .query(
+                "SELECT type, state_key, event_id FROM state_groups_state
+                    WHERE state_group = $1 AND room_id = $2",
+                &[&sg, &room_id],
+            )
+            .expect("could not query state_groups_state");
+
+        for row in &rows {
+            let etype: String = row.get(0);
+            let state_key: String = row.get(1);
+            let event_id: String = row.get(2);
+
+            state_map.insert(&etype, &state_key, event_id.into());
+        }
+    }
+
+    state_map
+}
+
+/// Wraps a string so that it is formatted as an escaped, quoted Postgres
+/// literal when printed.
+pub struct PGEscapse<'a>(pub &'a str);
+
+impl<'a> fmt::Display for PGEscapse<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'")?;
+
+        for c in self.0.chars() {
+            if c == '\'' {
+                write!(f, "''")?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+
+        write!(f, "'")
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incremental compression: rather than recompressing an entire room every
+//! run, remember the highest state group we've already compressed (in the
+//! `state_compressor_progress` table) and only process groups produced
+//! since then, plus whatever ancestor snapshot is needed to resolve their
+//! `prev_state_group` chains.
+
+use std::collections::BTreeMap;
+
+use postgres::Connection;
+
+use crate::compressor::{Compressor, Stats};
+use crate::{database, window, StateGroupEntry};
+
+/// The result of a single incremental run.
+pub struct IncrementalResult {
+    /// The previous and new state, including any pre-existing ancestor
+    /// group materialized to resolve a `prev_state_group` chain.
+    pub old_map: BTreeMap<i64, StateGroupEntry>,
+    pub new_map: BTreeMap<i64, StateGroupEntry>,
+    /// The groups that were actually produced since the last run. Only
+    /// these should be written out / applied - `old_map`/`new_map` may also
+    /// contain an already-compressed ancestor that's present purely so the
+    /// compressor has something to delta against.
+    pub changed_ids: Vec<i64>,
+    pub stats: Stats,
+    /// The highest state group processed, to be persisted as the new
+    /// watermark once the caller has successfully applied the changes.
+    pub high_water_mark: i64,
+}
+
+/// Fetches and compresses only the state groups produced since the last
+/// incremental run for `room_id`. Returns `None` if there's nothing new to
+/// do.
+pub fn compress_incremental(
+    conn: &Connection,
+    room_id: &str,
+    level_sizes: &[usize],
+) -> Option<IncrementalResult> {
+    database::ensure_progress_table(conn);
+
+    let watermark = database::get_watermark(conn, room_id);
+
+    let new_ids = database::get_state_group_ids_range(
+        conn,
+        room_id,
+        watermark.map(|w| w + 1),
+        None,
+    );
+
+    let high_water_mark = *new_ids.last()?;
+
+    let mut new_map = database::get_data_from_db_range(conn, room_id, Some(new_ids[0]), None);
+
+    // Any of the new groups may delta against a group we compressed on a
+    // previous run, so pull that ancestor in as a self-contained snapshot.
+    window::materialize_boundary_roots(conn, room_id, new_ids[0], &mut new_map);
+
+    let old_map = new_map.clone();
+
+    // Only the newly-produced groups get assigned levels - a materialized
+    // boundary root is left untouched, purely as a lookup for resolving the
+    // delta against the last run's watermark.
+    let compressor = Compressor::compress_ids(&new_map, level_sizes, &new_ids);
+
+    // Merge the freshly-compressed entries over the original map (which may
+    // still contain a boundary root) so `prev_state_group` chains through it
+    // can still be resolved, while `changed_ids` stays the authoritative
+    // write scope.
+    let mut merged_new_map = new_map;
+    merged_new_map.extend(compressor.new_state_group_map);
+
+    Some(IncrementalResult {
+        old_map,
+        new_map: merged_new_map,
+        changed_ids: new_ids,
+        stats: compressor.stats,
+        high_water_mark,
+    })
+}
+
+/// Persists `high_water_mark` as the new watermark for `room_id`. Should
+/// only be called once the caller has successfully written/applied this
+/// run's changes.
+pub fn advance_watermark(conn: &Connection, room_id: &str, high_water_mark: i64) {
+    database::set_watermark(conn, room_id, high_water_mark);
+}
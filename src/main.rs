@@ -22,19 +22,26 @@ extern crate fallible_iterator;
 extern crate indicatif;
 extern crate jemallocator;
 extern crate postgres;
+extern crate r2d2;
+extern crate r2d2_postgres;
 extern crate rand;
 extern crate rayon;
 extern crate state_map;
 extern crate string_cache;
 
+mod allrooms;
+mod autotune;
 mod compressor;
 mod database;
+mod incremental;
+mod window;
 
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use compressor::Compressor;
 use database::PGEscapse;
+use postgres::Connection;
 
 use clap::{App, Arg};
 use indicatif::{ProgressBar, ProgressStyle};
@@ -123,7 +130,29 @@ fn main() {
                 .value_name("ROOM_ID")
                 .help("The room to process")
                 .takes_value(true)
-                .required(true),
+                .required_unless("all_rooms")
+                .conflicts_with("all_rooms"),
+        ).arg(
+            Arg::with_name("all_rooms")
+                .long("all_rooms")
+                .help("Compress every room found in state_groups_state, instead of a single --room_id")
+                .conflicts_with_all(&[
+                    "window_size",
+                    "incremental",
+                    "auto_levels",
+                    "verify_db",
+                    "output_file",
+                    "transactions",
+                    "max_state_group",
+                ]),
+        ).arg(
+            Arg::with_name("pool_size")
+                .long("pool_size")
+                .value_name("SIZE")
+                .help("Number of concurrent Postgres connections to use when compressing multiple rooms with --all_rooms")
+                .takes_value(true)
+                .default_value("4")
+                .requires("all_rooms"),
         ).arg(
             Arg::with_name("max_state_group")
                 .short("s")
@@ -142,6 +171,19 @@ fn main() {
                 .short("t")
                 .help("Whether to wrap each state group change in a transaction")
                 .requires("output_file"),
+        ).arg(
+            Arg::with_name("apply")
+                .long("apply")
+                .visible_alias("commit")
+                .help("Apply the compression directly to the database, instead of (or as well as) writing SQL to --output_file"),
+        ).arg(
+            Arg::with_name("apply_batch_size")
+                .long("apply_batch_size")
+                .value_name("SIZE")
+                .help("Number of changed state groups to write per transaction when --apply is given")
+                .takes_value(true)
+                .default_value("100")
+                .requires("apply"),
         ).arg(
             Arg::with_name("level_sizes")
                 .short("l")
@@ -159,6 +201,36 @@ fn main() {
                 ))
                 .default_value("100,50,25")
                 .takes_value(true),
+        ).arg(
+            Arg::with_name("window_size")
+                .long("window_size")
+                .value_name("SIZE")
+                .help("Process state groups in memory-bounded windows of this many groups, instead of loading the whole room at once")
+                .takes_value(true)
+                .conflicts_with("incremental"),
+        ).arg(
+            Arg::with_name("incremental")
+                .long("incremental")
+                .help("Only compress state groups produced since the last --incremental run, using a persisted watermark")
+                .requires("apply"),
+        ).arg(
+            Arg::with_name("auto_levels")
+                .long("auto_levels")
+                .help("Search for a --level_sizes configuration that minimizes the compression ratio, starting from --level_sizes as the default")
+                .conflicts_with_all(&["window_size", "incremental"]),
+        ).arg(
+            Arg::with_name("auto_levels_max")
+                .long("auto_levels_max")
+                .value_name("LEVELS")
+                .help("Maximum number of levels auto_levels is allowed to use")
+                .takes_value(true)
+                .default_value("5")
+                .requires("auto_levels"),
+        ).arg(
+            Arg::with_name("verify_db")
+                .long("verify_db")
+                .help("After --apply, re-fetch each changed group's state from the live database and compare it to the computed result, stopping at the first mismatch and exiting non-zero")
+                .requires("apply"),
         ).get_matches();
 
     let db_url = matches
@@ -169,21 +241,68 @@ fn main() {
         .value_of("output_file")
         .map(|path| File::create(path).unwrap());
 
-    let room_id = matches
-        .value_of("room_id")
-        .expect("room_id should be required since no file");
-
     let max_state_group = matches
         .value_of("max_state_group")
         .map(|s| s.parse().expect("max_state_group must be an integer"));
 
     let transactions = matches.is_present("transactions");
 
+    let apply = matches.is_present("apply");
+    let apply_batch_size = value_t_or_exit!(matches, "apply_batch_size", usize);
+
     let level_sizes = value_t_or_exit!(matches, "level_sizes", LevelSizes);
 
+    if matches.is_present("all_rooms") {
+        let pool_size = value_t_or_exit!(matches, "pool_size", u32);
+
+        run_all_rooms(db_url, &level_sizes.0, apply, apply_batch_size, pool_size);
+        return;
+    }
+
+    let room_id = matches
+        .value_of("room_id")
+        .expect("room_id should be required since no --all_rooms");
+
+    let window_size = matches
+        .value_of("window_size")
+        .map(|s| s.parse().expect("window_size must be an integer"));
+
+    // Open a single connection that we'll use both to fetch the existing
+    // state and, if asked, to apply the compression back to the DB.
+    let conn = database::connect(db_url);
+
+    if let Some(window_size) = window_size {
+        run_windowed(
+            &conn,
+            room_id,
+            max_state_group,
+            &level_sizes.0,
+            window_size,
+            &mut output_file,
+            transactions,
+            apply,
+            apply_batch_size,
+            matches.is_present("verify_db"),
+        );
+        return;
+    }
+
+    if matches.is_present("incremental") {
+        run_incremental(
+            &conn,
+            room_id,
+            &level_sizes.0,
+            &mut output_file,
+            transactions,
+            apply_batch_size,
+            matches.is_present("verify_db"),
+        );
+        return;
+    }
+
     // First we need to get the current state groups
     println!("Fetching state from DB for room '{}'...", room_id);
-    let state_group_map = database::get_data_from_db(db_url, room_id, max_state_group);
+    let state_group_map = database::get_data_from_db(&conn, room_id, max_state_group);
 
     println!("Number of state groups: {}", state_group_map.len());
 
@@ -195,9 +314,29 @@ fn main() {
 
     // Now we actually call the compression algorithm.
 
-    println!("Compressing state...");
+    let compressor = if matches.is_present("auto_levels") {
+        let auto_levels_max = value_t_or_exit!(matches, "auto_levels_max", usize);
+
+        println!(
+            "Searching for level_sizes (starting from {:?}, max {} levels)...",
+            level_sizes.0, auto_levels_max
+        );
+
+        let result =
+            autotune::auto_tune_levels(&state_group_map, &level_sizes.0, auto_levels_max);
+
+        println!(
+            "Chose level_sizes {:?} with ratio {:.2}%",
+            result.level_sizes,
+            result.ratio * 100.
+        );
+
+        result.compressor
+    } else {
+        println!("Compressing state...");
 
-    let compressor = Compressor::compress(&state_group_map, &level_sizes.0);
+        Compressor::compress(&state_group_map, &level_sizes.0)
+    };
 
     let new_state_group_map = compressor.new_state_group_map;
 
@@ -247,55 +386,7 @@ fn main() {
             let new_entry = &new_state_group_map[sg];
 
             if old_entry != new_entry {
-                if transactions {
-                    writeln!(output, "BEGIN;").unwrap();
-                }
-
-                writeln!(
-                    output,
-                    "DELETE FROM state_group_edges WHERE state_group = {};",
-                    sg
-                )
-                .unwrap();
-
-                if let Some(prev_sg) = new_entry.prev_state_group {
-                    writeln!(output, "INSERT INTO state_group_edges (state_group, prev_state_group) VALUES ({}, {});", sg, prev_sg).unwrap();
-                }
-
-                writeln!(
-                    output,
-                    "DELETE FROM state_groups_state WHERE state_group = {};",
-                    sg
-                )
-                .unwrap();
-                if !new_entry.state_map.is_empty() {
-                    writeln!(output, "INSERT INTO state_groups_state (state_group, room_id, type, state_key, event_id) VALUES").unwrap();
-                    let mut first = true;
-                    for ((t, s), e) in new_entry.state_map.iter() {
-                        if first {
-                            write!(output, "     ").unwrap();
-                            first = false;
-                        } else {
-                            write!(output, "    ,").unwrap();
-                        }
-                        writeln!(
-                            output,
-                            "({}, {}, {}, {}, {})",
-                            sg,
-                            PGEscapse(room_id),
-                            PGEscapse(t),
-                            PGEscapse(s),
-                            PGEscapse(e)
-                        )
-                        .unwrap();
-                    }
-                    writeln!(output, ";").unwrap();
-                }
-
-                if transactions {
-                    writeln!(output, "COMMIT;").unwrap();
-                }
-                writeln!(output).unwrap();
+                write_sql_for_entry(output, room_id, *sg, new_entry, transactions);
             }
 
             pb.inc(1);
@@ -337,4 +428,321 @@ fn main() {
     pb.finish();
 
     println!("New state map matches old one");
+
+    if apply {
+        println!("Applying changes to database...");
+
+        let ids: Vec<i64> = state_group_map.keys().cloned().collect();
+
+        let groups_applied = database::apply_changes(
+            &conn,
+            room_id,
+            &state_group_map,
+            &new_state_group_map,
+            &ids,
+            apply_batch_size,
+        )
+        .expect("failed to apply changes to database");
+
+        println!("Applied changes to {} state groups", groups_applied);
+
+        if matches.is_present("verify_db") {
+            verify_against_db(&conn, room_id, &state_group_map, &new_state_group_map);
+        }
+    }
+}
+
+/// Re-fetches each changed group's collapsed state from the live database
+/// and compares it to `new_state_group_map`, stopping and exiting non-zero
+/// as soon as one doesn't match rather than scanning the whole room.
+fn verify_against_db(
+    conn: &Connection,
+    room_id: &str,
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    new_state_group_map: &BTreeMap<i64, StateGroupEntry>,
+) {
+    println!("Verifying applied changes against the live database...");
+
+    for (sg, old_entry) in state_group_map {
+        let new_entry = &new_state_group_map[sg];
+
+        if old_entry == new_entry {
+            continue;
+        }
+
+        let expected = collapse_state_maps(new_state_group_map, *sg);
+        let actual = database::fetch_collapsed_state_from_db(conn, room_id, *sg);
+
+        if expected != actual {
+            eprintln!(
+                "State group {} does not match the live database after apply",
+                sg
+            );
+            eprintln!("Expected: {:#?}", expected);
+            eprintln!("Actual: {:#?}", actual);
+            std::process::exit(1);
+        }
+    }
+
+    println!("Live database matches computed state for all changed groups");
+}
+
+/// Writes the `DELETE`/`INSERT` statements needed to move `state_group` from
+/// its old row to `new_entry`, optionally wrapped in its own transaction.
+fn write_sql_for_entry(
+    output: &mut File,
+    room_id: &str,
+    state_group: i64,
+    new_entry: &StateGroupEntry,
+    transactions: bool,
+) {
+    if transactions {
+        writeln!(output, "BEGIN;").unwrap();
+    }
+
+    writeln!(
+        output,
+        "DELETE FROM state_group_edges WHERE state_group = {};",
+        state_group
+    )
+    .unwrap();
+
+    if let Some(prev_sg) = new_entry.prev_state_group {
+        writeln!(output, "INSERT INTO state_group_edges (state_group, prev_state_group) VALUES ({}, {});", state_group, prev_sg).unwrap();
+    }
+
+    writeln!(
+        output,
+        "DELETE FROM state_groups_state WHERE state_group = {};",
+        state_group
+    )
+    .unwrap();
+    if !new_entry.state_map.is_empty() {
+        writeln!(output, "INSERT INTO state_groups_state (state_group, room_id, type, state_key, event_id) VALUES").unwrap();
+        let mut first = true;
+        for ((t, s), e) in new_entry.state_map.iter() {
+            if first {
+                write!(output, "     ").unwrap();
+                first = false;
+            } else {
+                write!(output, "    ,").unwrap();
+            }
+            writeln!(
+                output,
+                "({}, {}, {}, {}, {})",
+                state_group,
+                PGEscapse(room_id),
+                PGEscapse(t),
+                PGEscapse(s),
+                PGEscapse(e)
+            )
+            .unwrap();
+        }
+        writeln!(output, ";").unwrap();
+    }
+
+    if transactions {
+        writeln!(output, "COMMIT;").unwrap();
+    }
+    writeln!(output).unwrap();
+}
+
+/// Memory-bounded equivalent of the main compression path: processes
+/// `room_id` in ascending windows of `window_size` state groups, writing out
+/// SQL and/or applying each window's changes before the next window is
+/// loaded, so peak memory stays bounded by the window size rather than the
+/// size of the room.
+#[allow(clippy::too_many_arguments)]
+fn run_windowed(
+    conn: &Connection,
+    room_id: &str,
+    max_state_group: Option<i64>,
+    level_sizes: &[usize],
+    window_size: usize,
+    output_file: &mut Option<File>,
+    transactions: bool,
+    apply: bool,
+    apply_batch_size: usize,
+    verify_db: bool,
+) {
+    println!(
+        "Compressing room '{}' in windows of {} state groups...",
+        room_id, window_size
+    );
+
+    let mut groups_applied = 0;
+
+    let result = window::compress_windowed(
+        conn,
+        room_id,
+        max_state_group,
+        level_sizes,
+        window_size,
+        |window_ids, window_map, new_window_map| {
+            if let Some(output) = output_file {
+                for sg in window_ids {
+                    let old_entry = &window_map[sg];
+                    let new_entry = &new_window_map[sg];
+
+                    if old_entry != new_entry {
+                        write_sql_for_entry(output, room_id, *sg, new_entry, transactions);
+                    }
+                }
+            }
+
+            if apply {
+                database::apply_changes(
+                    conn,
+                    room_id,
+                    window_map,
+                    new_window_map,
+                    window_ids,
+                    apply_batch_size,
+                )
+                .map(|n| groups_applied += n)
+                .expect("failed to apply changes to database");
+
+                if verify_db {
+                    verify_against_db(conn, room_id, window_map, new_window_map);
+                }
+            }
+        },
+    );
+
+    let ratio =
+        (result.compressed_summed_size as f64) / (result.original_summed_size as f64);
+
+    println!(
+        "Number of rows after compression: {} ({:.2}%)",
+        result.compressed_summed_size,
+        ratio * 100.
+    );
+
+    println!("Compression Statistics:");
+    println!(
+        "  Number of forced resets due to lacking prev: {}",
+        result.stats.resets_no_suitable_prev
+    );
+    println!(
+        "  Number of compressed rows caused by the above: {}",
+        result.stats.resets_no_suitable_prev_size
+    );
+    println!(
+        "  Number of state groups changed: {}",
+        result.stats.state_groups_changed
+    );
+
+    if apply {
+        println!("Applied changes to {} state groups", groups_applied);
+    }
+}
+
+/// Compresses only the state groups produced since the last `--incremental`
+/// run for `room_id`, then advances the persisted watermark once those
+/// changes have been successfully applied.
+#[allow(clippy::too_many_arguments)]
+fn run_incremental(
+    conn: &Connection,
+    room_id: &str,
+    level_sizes: &[usize],
+    output_file: &mut Option<File>,
+    transactions: bool,
+    apply_batch_size: usize,
+    verify_db: bool,
+) {
+    println!("Looking for state groups produced since the last run...");
+
+    let result = match incremental::compress_incremental(conn, room_id, level_sizes) {
+        Some(result) => result,
+        None => {
+            println!("No new state groups to compress.");
+            return;
+        }
+    };
+
+    println!(
+        "Compressing {} new state groups (up to {})...",
+        result.changed_ids.len(),
+        result.high_water_mark
+    );
+
+    if let Some(output) = output_file {
+        for sg in &result.changed_ids {
+            let old_entry = &result.old_map[sg];
+            let new_entry = &result.new_map[sg];
+
+            if old_entry != new_entry {
+                write_sql_for_entry(output, room_id, *sg, new_entry, transactions);
+            }
+        }
+    }
+
+    println!("Applying changes to database...");
+
+    let groups_applied = database::apply_changes(
+        conn,
+        room_id,
+        &result.old_map,
+        &result.new_map,
+        &result.changed_ids,
+        apply_batch_size,
+    )
+    .expect("failed to apply changes to database");
+
+    if verify_db {
+        verify_against_db(conn, room_id, &result.old_map, &result.new_map);
+    }
+
+    incremental::advance_watermark(conn, room_id, result.high_water_mark);
+
+    println!(
+        "Applied changes to {} state groups, watermark now {}",
+        groups_applied, result.high_water_mark
+    );
+}
+
+/// Discovers and compresses every room in the database, printing a per-room
+/// summary table followed by the totals across all of them.
+fn run_all_rooms(db_url: &str, level_sizes: &[usize], apply: bool, apply_batch_size: usize, pool_size: u32) {
+    println!("Discovering rooms...");
+
+    let summaries =
+        allrooms::compress_all_rooms(db_url, level_sizes, apply, apply_batch_size, pool_size);
+
+    println!(
+        "{:<60} {:>12} {:>12} {:>8} {:>10}",
+        "room_id", "original", "compressed", "ratio", "resets"
+    );
+
+    let mut total_original = 0;
+    let mut total_compressed = 0;
+    let mut total_resets = 0;
+
+    for summary in &summaries {
+        let ratio = (summary.compressed_size as f64) / (summary.original_size as f64);
+
+        println!(
+            "{:<60} {:>12} {:>12} {:>7.2}% {:>10}",
+            summary.room_id,
+            summary.original_size,
+            summary.compressed_size,
+            ratio * 100.,
+            summary.stats.resets_no_suitable_prev
+        );
+
+        total_original += summary.original_size;
+        total_compressed += summary.compressed_size;
+        total_resets += summary.stats.resets_no_suitable_prev;
+    }
+
+    let total_ratio = (total_compressed as f64) / (total_original as f64);
+
+    println!(
+        "\nCompressed {} rooms: {} -> {} rows ({:.2}%), {} forced resets",
+        summaries.len(),
+        total_original,
+        total_compressed,
+        total_ratio * 100.,
+        total_resets
+    );
 }
@@ -0,0 +1,140 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory-bounded compression: instead of loading an entire room's state
+//! groups into memory at once, `compress_windowed` walks them in ascending
+//! windows of a bounded size, so peak memory is proportional to the window
+//! size rather than the size of the room.
+//!
+//! A window's groups can still have a `prev_state_group` that points below
+//! the window's lower bound (i.e. into a window we've already processed and
+//! dropped). To resolve those without holding earlier windows in memory, the
+//! referenced group's full state is collapsed once from the database and
+//! inserted into the window as a synthetic root (a `StateGroupEntry` with no
+//! `prev_state_group` of its own), giving the compressor a self-contained
+//! snapshot to delta against.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use postgres::Connection;
+
+use crate::compressor::{Compressor, Stats};
+use crate::{database, StateGroupEntry};
+
+/// Aggregate stats and totals across every window processed by
+/// `compress_windowed`.
+#[derive(Default)]
+pub struct WindowedResult {
+    pub original_summed_size: usize,
+    pub compressed_summed_size: usize,
+    pub stats: Stats,
+}
+
+/// Runs the compressor over `room_id` in ascending windows of at most
+/// `window_size` state groups, calling `flush` with the original and
+/// compressed maps for each window as soon as it's done so the caller can
+/// write out SQL/apply changes and let the window's memory be freed before
+/// the next one is loaded.
+pub fn compress_windowed<F>(
+    conn: &Connection,
+    room_id: &str,
+    max_state_group: Option<i64>,
+    level_sizes: &[usize],
+    window_size: usize,
+    mut flush: F,
+) -> WindowedResult
+where
+    F: FnMut(&[i64], &BTreeMap<i64, StateGroupEntry>, &BTreeMap<i64, StateGroupEntry>),
+{
+    let ids = database::get_state_group_ids(conn, room_id, max_state_group);
+
+    let mut result = WindowedResult::default();
+
+    for window_ids in ids.chunks(window_size.max(1)) {
+        let window_start = window_ids[0];
+        let window_end = *window_ids.last().expect("window should be non-empty");
+
+        let mut window_map = database::get_data_from_db_range(
+            conn,
+            room_id,
+            Some(window_start),
+            Some(window_end),
+        );
+
+        materialize_boundary_roots(conn, room_id, window_start, &mut window_map);
+
+        result.original_summed_size += window_ids
+            .iter()
+            .map(|sg| window_map[sg].state_map.len())
+            .sum::<usize>();
+
+        // Only the groups actually in this window get assigned levels - a
+        // materialized boundary root is left untouched, purely as a lookup
+        // for resolving the cross-window delta against.
+        let compressor = Compressor::compress_ids(&window_map, level_sizes, window_ids);
+
+        result.compressed_summed_size += window_ids
+            .iter()
+            .map(|sg| compressor.new_state_group_map[sg].state_map.len())
+            .sum::<usize>();
+        result.stats.resets_no_suitable_prev += compressor.stats.resets_no_suitable_prev;
+        result.stats.resets_no_suitable_prev_size += compressor.stats.resets_no_suitable_prev_size;
+        result.stats.state_groups_changed += compressor.stats.state_groups_changed;
+
+        // Merge the freshly-compressed entries over the original window map
+        // (which may still contain a boundary root) so that callers can keep
+        // resolving `prev_state_group` chains through it, while restricting
+        // anything they write out to `window_ids`.
+        let mut new_window_map = window_map.clone();
+        new_window_map.extend(compressor.new_state_group_map);
+
+        flush(window_ids, &window_map, &new_window_map);
+
+        // `window_map` and `new_window_map` are dropped here, before the next
+        // window is loaded, so peak memory stays bounded by `window_size`.
+    }
+
+    result
+}
+
+/// For any group in `map` whose `prev_state_group` points below `boundary`,
+/// fetch that ancestor's full history once, collapse it to a single
+/// `StateMap`, and insert it into `map` as a synthetic root so the
+/// compressor has a self-contained snapshot to delta against. Shared by the
+/// windowed mode (where `boundary` is a window's lower bound) and the
+/// incremental mode (where it's the persisted watermark).
+pub(crate) fn materialize_boundary_roots(
+    conn: &Connection,
+    room_id: &str,
+    boundary: i64,
+    map: &mut BTreeMap<i64, StateGroupEntry>,
+) {
+    let boundary_groups: BTreeSet<i64> = map
+        .values()
+        .filter_map(|entry| entry.prev_state_group)
+        .filter(|&sg| sg < boundary)
+        .collect();
+
+    for boundary_sg in boundary_groups {
+        let collapsed = database::fetch_collapsed_state_from_db(conn, room_id, boundary_sg);
+
+        map.insert(
+            boundary_sg,
+            StateGroupEntry {
+                prev_state_group: None,
+                state_map: collapsed,
+            },
+        );
+    }
+}